@@ -3,7 +3,7 @@ mod test_extensions;
 mod strip;
 mod instructions;
 
-use crate::strip::{create_strip, LedController};
+use crate::strip::{create_strip, ColorOrder, LedController, DEFAULT_MAX_SPI_TRANSFER_SIZE};
 use crate::timings::{get_signal_representation_in_bytes, DEFAULT_WS2812B_TIMING_REQUIREMENTS};
 use bitvec::order::{BitOrder, Lsb0, Msb0};
 use rppal::spi::BitOrder::{LsbFirst, MsbFirst};
@@ -54,35 +54,56 @@ fn test_pin() -> Result<(), Box<dyn Error>> {
     Ok(())
 }
 
-struct SpiAdapter(Spi);
+/// Bridges a Raspberry Pi [`Spi`] peripheral to an `embedded-hal` [`SpiBus`], so the strip's
+/// generic write path can drive real Pi hardware.
+struct SpiAdapter<'a>(&'a mut Spi);
 
-impl ErrorType for SpiAdapter { type Error = ErrorKind; }
+/// Wraps [`rppal::spi::Error`] so it can report [`embedded_hal::spi::ErrorKind`] without losing
+/// the original failure for logging/debugging.
+#[derive(Debug)]
+struct SpiAdapterError(rppal::spi::Error);
 
-impl SpiBus<u8> for SpiAdapter {
+impl Display for SpiAdapterError {
+    fn fmt(&self, formatter: &mut Formatter<'_>) -> std::fmt::Result {
+        Display::fmt(&self.0, formatter)
+    }
+}
+
+impl Error for SpiAdapterError {}
+
+impl embedded_hal::spi::Error for SpiAdapterError {
+    fn kind(&self) -> ErrorKind {
+        ErrorKind::Other
+    }
+}
+
+impl<'a> ErrorType for SpiAdapter<'a> { type Error = SpiAdapterError; }
+
+impl<'a> SpiBus<u8> for SpiAdapter<'a> {
     fn read(&mut self, words: &mut [u8]) -> Result<(), Self::Error> {
-        self.0.read(words).map_err(|error| ErrorKind::Other)?;
+        self.0.read(words).map_err(SpiAdapterError)?;
         Ok(())
     }
 
     fn write(&mut self, words: &[u8]) -> Result<(), Self::Error> {
-        self.0.write(words).map_err(|error| ErrorKind::Other)?;
+        self.0.write(words).map_err(SpiAdapterError)?;
         Ok(())
     }
 
     fn transfer(&mut self, read: &mut [u8], write: &[u8]) -> Result<(), Self::Error> {
-        self.0.read(read).map_err(|error| ErrorKind::Other)?;
-        self.0.write(write).map_err(|error| ErrorKind::Other)?;
+        self.0.transfer(read, write).map_err(SpiAdapterError)?;
         Ok(())
     }
 
     fn transfer_in_place(&mut self, words: &mut [u8]) -> Result<(), Self::Error> {
-        self.0.write(words).map_err(|error| ErrorKind::Other)?;
-        self.0.read(words).map_err(|error| ErrorKind::Other)?;
+        let write_buffer = words.to_vec();
+        self.0.transfer(words, &write_buffer).map_err(SpiAdapterError)?;
         Ok(())
     }
 
     fn flush(&mut self) -> Result<(), Self::Error> {
-        self.0.flush().map_err(|error| ErrorKind::Other)?;
+        // rppal's Spi writes are synchronous (the ioctl doesn't return until the transfer is
+        // done), and it exposes no separate flush, so there's nothing left to wait for here.
         Ok(())
     }
 }
@@ -136,9 +157,10 @@ pub fn test_strip<DataBitsOrdering>(spi: &mut Spi) -> Result<(), Box<dyn Error>>
 where
     DataBitsOrdering: BitOrder,
 {
-    let mut strip = create_strip::<DataBitsOrdering>(3, get_signal_representation_in_bytes(SPI_CLOCK_SPEED, DEFAULT_WS2812B_TIMING_REQUIREMENTS));
+    let mut strip = create_strip::<DataBitsOrdering>(3, get_signal_representation_in_bytes(SPI_CLOCK_SPEED, DEFAULT_WS2812B_TIMING_REQUIREMENTS)?, ColorOrder::Grb, false);
+    let mut adapter = SpiAdapter(spi);
 
-    strip.write_to_spi_blocking(spi)?;
+    strip.write_blocking(&mut adapter, DEFAULT_MAX_SPI_TRANSFER_SIZE)?;
     thread::sleep(Duration::from_secs(1));
 
 
@@ -155,7 +177,7 @@ where
         println!("setting color to {}", color);
         strip.set_all_leds(color.red, color.green, color.blue);
         strip.print_buffer();
-        strip.write_to_spi_blocking(spi).unwrap();
+        strip.write_blocking(&mut adapter, DEFAULT_MAX_SPI_TRANSFER_SIZE).unwrap();
         thread::sleep(Duration::from_secs(5));
     });
 
@@ -2,21 +2,69 @@ use crate::instructions::SPI_INSTRUCTION_WRITE;
 use crate::timings::WS2812BSpecification;
 use bitvec::macros::internal::funty::Fundamental;
 use bitvec::prelude::*;
-use rppal::spi::Spi;
+use embedded_hal::spi::SpiBus;
 use std::error::Error;
 use std::thread;
 use std::time::Duration;
+use crate::tests;
+#[cfg(test)]
+use crate::test_extensions::CaptureSpi;
+#[cfg(test)]
+use crate::timings::{get_signal_representation_in_bytes, DEFAULT_WS2812B_TIMING_REQUIREMENTS};
+
+/// Default cap on a single SPI transfer, matching the Linux spidev driver's default
+/// `bufsiz` module parameter. Transfers larger than this are rejected with "message too long".
+pub const DEFAULT_MAX_SPI_TRANSFER_SIZE: usize = 4096;
 
 pub trait LedController {
     fn len(&self) -> usize;
     fn reset_leds(&mut self);
     fn set_led(&mut self, position: usize, red: u8, green: u8, blue: u8);
+    fn set_led_rgbw(&mut self, position: usize, red: u8, green: u8, blue: u8, white: u8);
     fn set_all_leds(&mut self, red: u8, green: u8, blue: u8);
-    fn write_to_spi(&mut self, spi: &mut Spi) -> Result<(), Box<dyn Error>>;
-    fn write_to_spi_blocking(&mut self, spi: &mut Spi) -> Result<(), Box<dyn Error>>;
+    fn write<S: SpiBus<u8>>(&mut self, spi: &mut S, max_transfer_size: usize) -> Result<(), Box<dyn Error>>;
+    fn write_blocking<S: SpiBus<u8>>(&mut self, spi: &mut S, max_transfer_size: usize) -> Result<(), Box<dyn Error>>;
     fn print_buffer(&self);
 }
 
+/// Order in which the red, green and blue channels are transmitted to the strip.
+///
+/// WS2812B-family chips don't agree on a single wire order, so this must match whatever the
+/// physical strip expects (check its datasheet).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ColorOrder {
+    Rgb,
+    Rbg,
+    Grb,
+    Gbr,
+    Brg,
+    Bgr,
+}
+
+impl ColorOrder {
+    /// Indices into `[red, green, blue]`, in the order they should be transmitted.
+    fn transmit_order(&self) -> [usize; 3] {
+        match self {
+            ColorOrder::Rgb => [0, 1, 2],
+            ColorOrder::Rbg => [0, 2, 1],
+            ColorOrder::Grb => [1, 0, 2],
+            ColorOrder::Gbr => [1, 2, 0],
+            ColorOrder::Brg => [2, 0, 1],
+            ColorOrder::Bgr => [2, 1, 0],
+        }
+    }
+
+    /// Debug label (`"R"`/`"G"`/`"B"`) for whichever channel is transmitted at `transmit_slot`.
+    fn channel_label(&self, transmit_slot: usize) -> &'static str {
+        match self.transmit_order()[transmit_slot] {
+            0 => "R",
+            1 => "G",
+            2 => "B",
+            _ => unreachable!(),
+        }
+    }
+}
+
 /// Describes a part of the buffer (for debugging purposes).
 #[derive(Clone)]
 struct BufferPart {
@@ -35,8 +83,12 @@ struct WS2812BStripSpecification {
     color_values: Vec<Vec<u8>>,
     /// Description of the buffer contents.
     buffer_parts: Vec<BufferPart>,
-    /// How many colors can one LED represent (e.g. 3 for RGB).
-    colors_per_led: usize,
+    /// Order in which the red, green and blue channels are transmitted.
+    color_order: ColorOrder,
+    /// Whether each LED also has a white channel (e.g. SK6812 RGBW), transmitted last.
+    has_white_channel: bool,
+    /// How many channels one LED represents (3 for RGB, 4 for RGBW).
+    channels_per_led: usize,
     /// How many colors values can be represented (usually values are in range 0 to 255 inclusive).
     possible_color_values: usize,
     /// How many bytes of data are necessary to represent each color.
@@ -50,6 +102,16 @@ struct WS2812BStrip {
     specification: WS2812BStripSpecification,
 }
 
+impl WS2812BStrip {
+    /// Writes one channel's encoded color data into the buffer, advancing `led_data_index_in_buffer` past it.
+    fn write_channel(&mut self, led_data_index_in_buffer: &mut usize, color_value: u8) {
+        self.specification.color_values[color_value.as_usize()].iter().for_each(|data_byte| {
+            self.buffer[*led_data_index_in_buffer] = *data_byte;
+            *led_data_index_in_buffer += 1;
+        });
+    }
+}
+
 impl LedController for WS2812BStrip {
     fn len(&self) -> usize {
         self.specification.leds_count
@@ -60,13 +122,19 @@ impl LedController for WS2812BStrip {
     }
 
     fn set_led(&mut self, position: usize, red: u8, green: u8, blue: u8) {
-        let mut led_data_index_in_buffer = ((position * self.specification.bytes_of_data_per_color) * self.specification.colors_per_led);
-        [green, red, blue].iter().for_each(|color_value| {
-            self.specification.color_values[color_value.as_usize()].iter().for_each(|data_byte| {
-                self.buffer[led_data_index_in_buffer] = *data_byte;
-                led_data_index_in_buffer += 1;
-            });
-        });
+        self.set_led_rgbw(position, red, green, blue, 0);
+    }
+
+    fn set_led_rgbw(&mut self, position: usize, red: u8, green: u8, blue: u8, white: u8) {
+        let mut led_data_index_in_buffer = (position * self.specification.bytes_of_data_per_color) * self.specification.channels_per_led;
+        let rgb = [red, green, blue];
+
+        for channel_index in self.specification.color_order.transmit_order() {
+            self.write_channel(&mut led_data_index_in_buffer, rgb[channel_index]);
+        }
+        if self.specification.has_white_channel {
+            self.write_channel(&mut led_data_index_in_buffer, white);
+        }
     }
 
     fn set_all_leds(&mut self, red: u8, green: u8, blue: u8) {
@@ -75,14 +143,22 @@ impl LedController for WS2812BStrip {
         }
     }
 
-    fn write_to_spi(&mut self, spi: &mut Spi) -> Result<(), Box<dyn Error>> {
+    fn write<S: SpiBus<u8>>(&mut self, spi: &mut S, max_transfer_size: usize) -> Result<(), Box<dyn Error>> {
         println!("writing buffer to SPI");
-        spi.write(&self.buffer[0..self.buffer.len()])?;
+        // spidev caps a single transfer's size, so split the buffer into back-to-back transfers.
+        // Each `spi.write()` here is its own ioctl, so there's no hard guarantee the gap between
+        // chunks stays under the WS2812B reset threshold (the minimum `latch_low_voltage_time`,
+        // 250us in `DEFAULT_WS2812B_TIMING_REQUIREMENTS`) the way a single
+        // `spidev_ioc_transfer` array with `cs_change = 0` per segment would provide; in practice
+        // the gap is small enough, but a latency spike between chunks could still force a latch.
+        for chunk in self.buffer.chunks(max_transfer_size.max(1)) {
+            spi.write(chunk).map_err(|error| -> Box<dyn Error> { format!("SPI write failed: {error:?}").into() })?;
+        }
         Ok(())
     }
 
-    fn write_to_spi_blocking(&mut self, spi: &mut Spi) -> Result<(), Box<dyn Error>> {
-        self.write_to_spi(spi)?;
+    fn write_blocking<S: SpiBus<u8>>(&mut self, spi: &mut S, max_transfer_size: usize) -> Result<(), Box<dyn Error>> {
+        self.write(spi, max_transfer_size)?;
         thread::sleep(Duration::from_secs(1));
         Ok(())
     }
@@ -122,35 +198,28 @@ impl LedController for WS2812BStrip {
     }
 }
 
-pub fn create_strip<DataBitsOrdering>(leds_count: usize, specification: WS2812BSpecification) -> impl LedController
+pub fn create_strip<DataBitsOrdering>(leds_count: usize, specification: WS2812BSpecification, color_order: ColorOrder, has_white_channel: bool) -> impl LedController
 where
     DataBitsOrdering: BitOrder,
 {
-    // 2 bytes per bit of data
-    // 1 byte per color (R, G, B) == 16 bytes of data per color
-    // 3 colors per LED = 48 bytes of data per LED
-    // 251 bytes for latch = 4016 bytes of data per latch (zeroed out)
-    let mut buffer = vec![0; (48 * leds_count) + /*4016 TODO: we put only 10 cause of spidev message too long error */ 10];
-    println!("buffer size: {}", buffer.len());
-    let colors_per_led = 3;
-    let bytes_of_data_per_color = 16;
+    let channels_per_led = if has_white_channel { 4 } else { 3 };
+    let bytes_of_data_per_color = specification.bytes_of_data_per_color;
+    let bytes_per_bit = bytes_of_data_per_color / 8;
     let possible_color_values = 256;
 
-    // TODO: populate based on signal specification.
+    let latch_size = specification.latch.len();
+    let buffer = vec![0; (bytes_of_data_per_color * channels_per_led * leds_count) + latch_size];
+    println!("buffer size: {}", buffer.len());
+
     let mut color_values: Vec<Vec<u8>> = vec![vec![0; bytes_of_data_per_color]; possible_color_values];
 
-    for color_value in 0..possible_color_values {
+    for (color_value, encoded) in color_values.iter_mut().enumerate() {
         let color_value_as_array = &[color_value.as_u8()];
         let bits = color_value_as_array.view_bits::<DataBitsOrdering>();
         for (bit_position, bit) in bits.iter().enumerate() {
-            // TODO: this may be more or fewer than two bytes, should be determined from specification
-            if bit.as_bool() {
-                color_values[color_value][bit_position * 2] = specification.zero_code[0];
-                color_values[color_value][(bit_position * 2) + 1] = specification.zero_code[1];
-            } else {
-                color_values[color_value][bit_position * 2] = specification.one_code[0];
-                color_values[color_value][(bit_position * 2) + 1] = specification.one_code[1];
-            }
+            let code = if *bit { &specification.one_code } else { &specification.zero_code };
+            let destination_start = bit_position * bytes_per_bit;
+            encoded[destination_start..destination_start + bytes_per_bit].copy_from_slice(code);
         }
     }
 
@@ -159,33 +228,179 @@ where
         contents: "".to_string(),
         byte_start_inclusive: 0,
         byte_end_exclusive: 0
-    }; (leds_count * colors_per_led) + 1];
+    }; (leds_count * channels_per_led) + 1];
 
     for led_index in 0..leds_count {
-        buffer_parts[led_index * colors_per_led] = BufferPart {
-            contents: format!("LED {led_index:03} G").to_string(),
-            byte_start_inclusive: led_index * bytes_of_data_per_color * 3,
-            byte_end_exclusive: (led_index * bytes_of_data_per_color * 3) + bytes_of_data_per_color,
-        };
-        buffer_parts[(led_index * colors_per_led) + 1] = BufferPart {
-            contents: format!("LED {led_index:03} R").to_string(),
-            byte_start_inclusive: (led_index * bytes_of_data_per_color * 3) + bytes_of_data_per_color,
-            byte_end_exclusive: (led_index * bytes_of_data_per_color * 3) + (bytes_of_data_per_color * 2),
-        };
-        buffer_parts[(led_index * colors_per_led) + 2] = BufferPart {
-            contents: format!("LED {led_index:03} B").to_string(),
-            byte_start_inclusive: (led_index * bytes_of_data_per_color * 3) + (bytes_of_data_per_color * 2),
-            byte_end_exclusive: (led_index * bytes_of_data_per_color * 3) + (bytes_of_data_per_color * 3),
-        };
+        for channel_slot in 0..channels_per_led {
+            let label = if channel_slot < 3 { color_order.channel_label(channel_slot) } else { "W" };
+            let byte_start_inclusive = (led_index * bytes_of_data_per_color * channels_per_led) + (channel_slot * bytes_of_data_per_color);
+            buffer_parts[(led_index * channels_per_led) + channel_slot] = BufferPart {
+                contents: format!("LED {led_index:03} {label}").to_string(),
+                byte_start_inclusive,
+                byte_end_exclusive: byte_start_inclusive + bytes_of_data_per_color,
+            };
+        }
     }
-    buffer_parts[(leds_count * colors_per_led)] = BufferPart {
+    buffer_parts[leds_count * channels_per_led] = BufferPart {
         contents: "Latch".to_string(),
-        byte_start_inclusive: buffer_parts[(leds_count * colors_per_led) - 1].byte_end_exclusive,
+        byte_start_inclusive: buffer_parts[(leds_count * channels_per_led) - 1].byte_end_exclusive,
         byte_end_exclusive: buffer.len(),
     };
 
-    let specification = WS2812BStripSpecification { leds_count, color_values, buffer_parts, colors_per_led, possible_color_values, bytes_of_data_per_color };
+    let specification = WS2812BStripSpecification { leds_count, color_values, buffer_parts, color_order, has_white_channel, channels_per_led, possible_color_values, bytes_of_data_per_color };
     let mut result = WS2812BStrip { buffer, specification };
     result.reset_leds();
     result
 }
+
+#[cfg(test)]
+fn repeat_code(code: &[u8], bit_count: usize) -> Vec<u8> {
+    code.iter().cloned().cycle().take(code.len() * bit_count).collect()
+}
+
+tests! {
+    capture_spi_single_pixel_tests,
+
+    |(color_order, red, green, blue, transmit_order): (ColorOrder, u8, u8, u8, [usize; 3])| {
+        let specification = get_signal_representation_in_bytes(8_000_000, DEFAULT_WS2812B_TIMING_REQUIREMENTS).unwrap();
+        let zero_code = specification.zero_code.clone();
+        let one_code = specification.one_code.clone();
+        let mut strip = create_strip::<Msb0>(1, specification, color_order, false);
+
+        strip.set_led(0, red, green, blue);
+
+        let mut spi = CaptureSpi::new();
+        strip.write(&mut spi, DEFAULT_MAX_SPI_TRANSFER_SIZE).unwrap();
+
+        let channel_values = [red, green, blue];
+        let mut expected = Vec::new();
+        for channel_index in transmit_order {
+            let code = if channel_values[channel_index] == 0xFF { &one_code } else { &zero_code };
+            expected.extend(repeat_code(code, 8));
+        }
+        expected.resize(spi.captured.len(), 0);
+
+        assert_eq!(expected, spi.captured);
+    },
+
+    given_red_pixel_in_grb_order: (ColorOrder::Grb, 255, 0, 0, [1, 0, 2]),
+    given_green_pixel_in_grb_order: (ColorOrder::Grb, 0, 255, 0, [1, 0, 2]),
+    given_blue_pixel_in_grb_order: (ColorOrder::Grb, 0, 0, 255, [1, 0, 2]),
+    given_red_pixel_in_rgb_order: (ColorOrder::Rgb, 255, 0, 0, [0, 1, 2]),
+    given_red_pixel_in_rbg_order: (ColorOrder::Rbg, 255, 0, 0, [0, 2, 1]),
+    given_red_pixel_in_gbr_order: (ColorOrder::Gbr, 255, 0, 0, [1, 2, 0]),
+    given_red_pixel_in_brg_order: (ColorOrder::Brg, 255, 0, 0, [2, 0, 1]),
+    given_red_pixel_in_bgr_order: (ColorOrder::Bgr, 255, 0, 0, [2, 1, 0]),
+}
+
+tests! {
+    capture_spi_rgbw_tests,
+
+    |(red, green, blue, white): (u8, u8, u8, u8)| {
+        let specification = get_signal_representation_in_bytes(8_000_000, DEFAULT_WS2812B_TIMING_REQUIREMENTS).unwrap();
+        let zero_code = specification.zero_code.clone();
+        let one_code = specification.one_code.clone();
+        let mut strip = create_strip::<Msb0>(1, specification, ColorOrder::Grb, true);
+
+        strip.set_led_rgbw(0, red, green, blue, white);
+
+        let mut spi = CaptureSpi::new();
+        strip.write(&mut spi, DEFAULT_MAX_SPI_TRANSFER_SIZE).unwrap();
+
+        // Grb order transmits green, red, blue, then the white channel last.
+        let mut expected = Vec::new();
+        for channel_value in [green, red, blue, white] {
+            let code = if channel_value == 0xFF { &one_code } else { &zero_code };
+            expected.extend(repeat_code(code, 8));
+        }
+        expected.resize(spi.captured.len(), 0);
+
+        assert_eq!(expected, spi.captured);
+    },
+
+    given_white_only_pixel_lands_in_the_fourth_channel_slot: (0, 0, 0, 255),
+    given_rgbw_pixel_with_every_channel_set: (255, 255, 255, 255),
+}
+
+tests! {
+    capture_spi_bit_ordering_tests,
+
+    |is_msb_first: bool| {
+        let specification = get_signal_representation_in_bytes(8_000_000, DEFAULT_WS2812B_TIMING_REQUIREMENTS).unwrap();
+        let zero_code = specification.zero_code.clone();
+        let one_code = specification.one_code.clone();
+        let mut spi = CaptureSpi::new();
+
+        if is_msb_first {
+            let mut strip = create_strip::<Msb0>(1, specification, ColorOrder::Rgb, false);
+            strip.set_led(0, 1, 0, 0);
+            strip.write(&mut spi, DEFAULT_MAX_SPI_TRANSFER_SIZE).unwrap();
+        } else {
+            let mut strip = create_strip::<Lsb0>(1, specification, ColorOrder::Rgb, false);
+            strip.set_led(0, 1, 0, 0);
+            strip.write(&mut spi, DEFAULT_MAX_SPI_TRANSFER_SIZE).unwrap();
+        }
+
+        // Red is 0b00000001: MSB-first transmits the set bit last, LSB-first transmits it first.
+        let mut expected = Vec::new();
+        if is_msb_first {
+            expected.extend(repeat_code(&zero_code, 7));
+            expected.extend(repeat_code(&one_code, 1));
+        } else {
+            expected.extend(repeat_code(&one_code, 1));
+            expected.extend(repeat_code(&zero_code, 7));
+        }
+        expected.extend(repeat_code(&zero_code, 8)); // green
+        expected.extend(repeat_code(&zero_code, 8)); // blue
+        expected.resize(spi.captured.len(), 0);
+
+        assert_eq!(expected, spi.captured);
+    },
+
+    given_Msb0_ordering_places_set_bit_last: true,
+    given_Lsb0_ordering_places_set_bit_first: false,
+}
+
+tests! {
+    capture_spi_latch_region_tests,
+
+    |clock_speed_in_hz: u32| {
+        let specification = get_signal_representation_in_bytes(clock_speed_in_hz, DEFAULT_WS2812B_TIMING_REQUIREMENTS).unwrap();
+        let latch_len = specification.latch.len();
+        let mut strip = create_strip::<Msb0>(1, specification, ColorOrder::Grb, false);
+
+        strip.set_all_leds(255, 255, 255);
+
+        let mut spi = CaptureSpi::new();
+        strip.write(&mut spi, DEFAULT_MAX_SPI_TRANSFER_SIZE).unwrap();
+
+        assert_eq!(spi.captured.len(), strip.len() * 3 * 16 + latch_len);
+        let latch_region = &spi.captured[spi.captured.len() - latch_len..];
+        assert!(latch_region.iter().all(|byte| *byte == 0));
+    },
+
+    given_8MHz_should_end_with_a_fully_zeroed_latch_region: 8_000_000,
+}
+
+tests! {
+    capture_spi_chunked_write_tests,
+
+    |max_transfer_size: usize| {
+        let specification = get_signal_representation_in_bytes(8_000_000, DEFAULT_WS2812B_TIMING_REQUIREMENTS).unwrap();
+        let mut strip = create_strip::<Msb0>(1, specification, ColorOrder::Grb, false);
+        strip.set_led(0, 255, 128, 64);
+
+        let mut single_shot_spi = CaptureSpi::new();
+        strip.write(&mut single_shot_spi, DEFAULT_MAX_SPI_TRANSFER_SIZE).unwrap();
+
+        let mut chunked_spi = CaptureSpi::new();
+        strip.write(&mut chunked_spi, max_transfer_size).unwrap();
+
+        // Whatever the chunk size, the concatenation of every transfer must reconstruct the
+        // exact same buffer a single unchunked write would have sent.
+        assert_eq!(single_shot_spi.captured, chunked_spi.captured);
+    },
+
+    given_max_transfer_size_of_1_byte: 1,
+    given_max_transfer_size_of_7_bytes_not_a_multiple_of_the_code_length: 7,
+}
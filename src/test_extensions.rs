@@ -1,3 +1,52 @@
+/// An `embedded-hal` [`SpiBus`](embedded_hal::spi::SpiBus) that records every byte written to it
+/// instead of talking to real hardware, so waveform-level assertions can run without a Raspberry Pi.
+#[cfg(test)]
+#[derive(Default)]
+pub struct CaptureSpi {
+    pub captured: Vec<u8>,
+}
+
+#[cfg(test)]
+impl CaptureSpi {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+#[cfg(test)]
+impl embedded_hal::spi::ErrorType for CaptureSpi {
+    type Error = std::convert::Infallible;
+}
+
+#[cfg(test)]
+impl embedded_hal::spi::SpiBus<u8> for CaptureSpi {
+    fn read(&mut self, words: &mut [u8]) -> Result<(), Self::Error> {
+        words.fill(0);
+        Ok(())
+    }
+
+    fn write(&mut self, words: &[u8]) -> Result<(), Self::Error> {
+        self.captured.extend_from_slice(words);
+        Ok(())
+    }
+
+    fn transfer(&mut self, read: &mut [u8], write: &[u8]) -> Result<(), Self::Error> {
+        read.fill(0);
+        self.captured.extend_from_slice(write);
+        Ok(())
+    }
+
+    fn transfer_in_place(&mut self, words: &mut [u8]) -> Result<(), Self::Error> {
+        self.captured.extend_from_slice(words);
+        words.fill(0);
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), Self::Error> {
+        Ok(())
+    }
+}
+
 #[macro_export]
 macro_rules! tests {
     ($module_name:ident,$test_code:expr,$($test_case_name:ident:$test_case_parameters:expr,)*) => {
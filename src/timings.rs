@@ -1,5 +1,7 @@
 use crate::tests;
 use struct_iterable::Iterable;
+use std::error::Error;
+use std::fmt::{Display, Formatter};
 
 
 /// Specification for SPI signal timings for bit banging.
@@ -87,34 +89,136 @@ tests! {
     given_8MHz_should_return_true: (8_000_000, true),
 }
 
+#[derive(Debug)]
+pub struct TimingError(String);
+
+impl Display for TimingError {
+    fn fmt(&self, formatter: &mut Formatter<'_>) -> std::fmt::Result {
+        formatter.write_str(&self.0)
+    }
+}
+
+impl Error for TimingError {}
+
 pub struct WS2812BSpecification {
     pub zero_code: Vec<u8>,
     pub one_code: Vec<u8>,
     pub latch: Vec<u8>,
+    /// How many bytes of buffer one full color value (e.g. one channel, 0..=255) takes up once encoded.
+    pub bytes_of_data_per_color: usize,
 }
 
-pub fn get_signal_representation_in_bytes(clock_speed_in_hz: u32, requirements: WS2812BRequirements) -> WS2812BSpecification {
-    // TODO: actually figure this out
-    //         let minimumCycles = signal_requirements.minimum / nanos_per_cycle;
-    //         let typicalCycles = signal_requirements.typical / nanos_per_cycle;
-    //         let maximumCycles = signal_requirements.maximum / nanos_per_cycle;
-    //         println!("cycles for {}Hz and {}", clock_speed_in_hz, signal_name);
-    //         println!("\tminimum = {}", minimumCycles);
-    //         println!("\ttypical = {}", typicalCycles);
-    //         println!("\tmaximum = {}", maximumCycles);
-    WS2812BSpecification {
-        zero_code: vec![0b11100000, 0b00000000],
-        one_code: vec![0b11111100, 0b00000000],
-        latch: vec![0; 251],
+fn round_div(numerator: u32, denominator: u32) -> u32 {
+    (numerator + (denominator / 2)) / denominator
+}
+
+fn ceil_div(numerator: u32, denominator: u32) -> u32 {
+    numerator.div_ceil(denominator)
+}
+
+/// Finds the number of whole SPI clock cycles that best approximates `requirement.typical`,
+/// nudging it so `cycles * nanos_per_cycle` still lands within `requirement.minimum..=requirement.maximum`.
+fn cycles_within_requirement(requirement: &SignalTimingRequirementsInNs, nanos_per_cycle: u32, signal_name: &str) -> Result<u32, TimingError> {
+    let mut cycles = round_div(requirement.typical, nanos_per_cycle);
+
+    while cycles > 0 && cycles * nanos_per_cycle > requirement.maximum {
+        cycles -= 1;
     }
+
+    while cycles * nanos_per_cycle < requirement.minimum {
+        cycles += 1;
+        if cycles * nanos_per_cycle > requirement.maximum {
+            return Err(TimingError(format!(
+                "no integer cycle count for {signal_name} fits within {}..={} ns at {nanos_per_cycle} ns/cycle",
+                requirement.minimum, requirement.maximum,
+            )));
+        }
+    }
+
+    Ok(cycles)
+}
+
+/// Packs `high_cycles` ones followed by `low_cycles` zeros, MSB-first, zero-padding the final partial byte.
+fn pack_high_low_cycles(high_cycles: u32, low_cycles: u32) -> Vec<u8> {
+    let total_bits = high_cycles + low_cycles;
+    let mut bytes = vec![0u8; ceil_div(total_bits, 8) as usize];
+
+    for bit_index in 0..high_cycles {
+        let byte_index = (bit_index / 8) as usize;
+        let bit_offset_from_msb = 7 - (bit_index % 8);
+        bytes[byte_index] |= 1 << bit_offset_from_msb;
+    }
+
+    bytes
+}
+
+pub fn get_signal_representation_in_bytes(clock_speed_in_hz: u32, requirements: WS2812BRequirements) -> Result<WS2812BSpecification, TimingError> {
+    if clock_speed_in_hz == 0 {
+        return Err(TimingError("clock_speed_in_hz must be greater than 0".to_string()));
+    }
+
+    let nanos_per_cycle = get_nanos_per_cycle(clock_speed_in_hz);
+    if nanos_per_cycle == 0 {
+        return Err(TimingError(format!(
+            "clock_speed_in_hz of {clock_speed_in_hz} is too fast to represent (rounds down to 0 ns/cycle)"
+        )));
+    }
+
+    let zero_high_cycles = cycles_within_requirement(&requirements.zero_code_high_voltage_time, nanos_per_cycle, "zero_code_high_voltage_time")?;
+    let zero_low_cycles = cycles_within_requirement(&requirements.zero_code_low_voltage_time, nanos_per_cycle, "zero_code_low_voltage_time")?;
+    let one_high_cycles = cycles_within_requirement(&requirements.one_code_high_voltage_time, nanos_per_cycle, "one_code_high_voltage_time")?;
+    let one_low_cycles = cycles_within_requirement(&requirements.one_code_low_voltage_time, nanos_per_cycle, "one_code_low_voltage_time")?;
+
+    let mut zero_code = pack_high_low_cycles(zero_high_cycles, zero_low_cycles);
+    let mut one_code = pack_high_low_cycles(one_high_cycles, one_low_cycles);
+
+    // Every encoded bit must take up the same number of bytes regardless of its value, so pad
+    // the shorter of the two codes with extra low (zero) bytes to match the longer one.
+    let bytes_per_code = zero_code.len().max(one_code.len());
+    zero_code.resize(bytes_per_code, 0);
+    one_code.resize(bytes_per_code, 0);
+
+    let latch_cycles = ceil_div(requirements.latch_low_voltage_time.typical, nanos_per_cycle);
+    let latch = pack_high_low_cycles(0, latch_cycles);
+
+    Ok(WS2812BSpecification {
+        zero_code,
+        one_code,
+        latch,
+        bytes_of_data_per_color: bytes_per_code * 8,
+    })
 }
 
 tests! {
     get_signal_representation_in_bytes_tests,
 
-    |(input, expected): (u32, WS2812BSignalBytes)| {
-        let actual = get_signal_representation_in_bytes(input, DEFAULT_WS2812B_TIMING_REQUIREMENTS);
-        assert_eq!(*expected, *actual);
+    |(clock_speed_in_hz, expected): (u32, WS2812BSpecification)| {
+        let actual = get_signal_representation_in_bytes(clock_speed_in_hz, DEFAULT_WS2812B_TIMING_REQUIREMENTS).unwrap();
+        assert_eq!(expected.zero_code, actual.zero_code);
+        assert_eq!(expected.one_code, actual.one_code);
+        assert_eq!(expected.latch, actual.latch);
+        assert_eq!(expected.bytes_of_data_per_color, actual.bytes_of_data_per_color);
     },
+
+    given_8MHz_should_match_known_good_encoding: (8_000_000, WS2812BSpecification {
+        zero_code: vec![0b11100000, 0b00000000],
+        one_code: vec![0b11111100, 0b00000000],
+        latch: vec![0; 251],
+        bytes_of_data_per_color: 16,
+    }),
 }
 
+tests! {
+    get_signal_representation_in_bytes_error_tests,
+
+    |clock_speed_in_hz: u32| {
+        let actual = get_signal_representation_in_bytes(clock_speed_in_hz, DEFAULT_WS2812B_TIMING_REQUIREMENTS);
+        assert!(actual.is_err());
+    },
+
+    given_1Hz_should_return_err_because_nothing_fits: 1,
+    given_0Hz_should_return_err_instead_of_dividing_by_zero: 0,
+    given_clock_speed_above_1GHz_should_return_err_instead_of_dividing_by_zero: 1_000_000_001,
+    given_2GHz_should_return_err_instead_of_dividing_by_zero: 2_000_000_000,
+    given_u32_MAX_should_return_err_instead_of_dividing_by_zero: u32::MAX,
+}